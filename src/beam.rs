@@ -0,0 +1,164 @@
+//! Beam-frontier bookkeeping shared by [`crate::Decoder`] and
+//! [`crate::LexiconDecoder`]: merging same-pattern candidates via
+//! log-sum-exp and pruning to the best `beam_size` via a bounded min-heap.
+//! Both decoders otherwise track unrelated per-hypothesis state (LM state
+//! vs. KenLM state plus a lexicon position), so only this bookkeeping, not
+//! the surrounding decode loop, is shared.
+
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, BinaryHeap, HashMap},
+};
+
+/// A beam hypothesis mergeable and prunable by [`add_candidate`] and
+/// [`prune_to_beam`]. `Ord` must compare by score alone, which is what lets
+/// a candidate sit directly in the `BinaryHeap` `prune_to_beam` uses.
+pub(crate) trait BeamCandidate: Ord {
+    /// `(token, prev_blank)`: hypotheses sharing this pattern are the same
+    /// CTC output reached through different histories, and get merged.
+    fn pattern_key(&self) -> (i32, bool);
+    fn score(&self) -> f32;
+    fn set_score(&mut self, score: f32);
+}
+
+/// Merge `state` into `candidates`, keyed by [`BeamCandidate::pattern_key`]:
+/// candidates sharing a key are the same CTC output pattern reached through
+/// different histories, so their scores are combined via log-sum-exp instead
+/// of kept apart, matching the collapsing CTC itself performs. The fields of
+/// whichever candidate scored higher before merging (the more likely of the
+/// two histories) are kept for backtracking and LM state.
+pub(crate) fn add_candidate<C: BeamCandidate>(
+    candidates: &mut HashMap<(i32, bool), C>,
+    current_best_score: &mut f32,
+    beam_threshold: f32,
+    state: C,
+) {
+    if state.score() > *current_best_score {
+        *current_best_score = state.score();
+    }
+    if state.score() <= *current_best_score - beam_threshold {
+        return;
+    }
+    match candidates.entry(state.pattern_key()) {
+        Entry::Occupied(mut entry) => {
+            let existing = entry.get_mut();
+            let max_score = existing.score().max(state.score());
+            let min_score = existing.score().min(state.score());
+            let merged_score =
+                max_score + libm::log1p(libm::exp((min_score - max_score) as f64)) as f32;
+            if state.score() > existing.score() {
+                *existing = state;
+            }
+            existing.set_score(merged_score);
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(state);
+        }
+    }
+}
+
+/// Drain `candidates`, pruning to the best `beam_size` via a bounded
+/// min-heap instead of sorting every candidate.
+pub(crate) fn prune_to_beam<C: BeamCandidate>(
+    candidates: &mut HashMap<(i32, bool), C>,
+    current_best_score: f32,
+    beam_threshold: f32,
+    beam_size: usize,
+) -> Vec<C> {
+    let mut heap: BinaryHeap<Reverse<C>> = BinaryHeap::with_capacity(beam_size + 1);
+    for (_, candidate) in candidates.drain() {
+        if candidate.score() <= current_best_score - beam_threshold {
+            continue;
+        }
+        heap.push(Reverse(candidate));
+        if heap.len() > beam_size {
+            heap.pop();
+        }
+    }
+    heap.into_iter().map(|Reverse(state)| state).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    struct TestCandidate {
+        score: f32,
+        pattern: (i32, bool),
+    }
+
+    impl PartialEq for TestCandidate {
+        fn eq(&self, other: &Self) -> bool {
+            self.score == other.score
+        }
+    }
+
+    impl Eq for TestCandidate {}
+
+    impl PartialOrd for TestCandidate {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for TestCandidate {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.score.partial_cmp(&other.score).unwrap()
+        }
+    }
+
+    impl BeamCandidate for TestCandidate {
+        fn pattern_key(&self) -> (i32, bool) {
+            self.pattern
+        }
+
+        fn score(&self) -> f32 {
+            self.score
+        }
+
+        fn set_score(&mut self, score: f32) {
+            self.score = score;
+        }
+    }
+
+    #[test]
+    fn prune_to_beam_keeps_only_the_best_candidates() {
+        let mut candidates = HashMap::new();
+        let mut best_score = f32::MIN;
+        for (score, pattern) in [(1.0, (0, false)), (5.0, (1, false)), (3.0, (2, false))] {
+            add_candidate(
+                &mut candidates,
+                &mut best_score,
+                f32::MAX,
+                TestCandidate { score, pattern },
+            );
+        }
+
+        let pruned = prune_to_beam(&mut candidates, best_score, f32::MAX, 2);
+        let mut scores: Vec<f32> = pruned.iter().map(|c| c.score).collect();
+        scores.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(scores, vec![3.0, 5.0]);
+        assert!(candidates.is_empty());
+    }
+
+    #[test]
+    fn add_candidate_merges_same_pattern_via_log_sum_exp() {
+        let mut candidates = HashMap::new();
+        let mut best_score = f32::MIN;
+        for _ in 0..2 {
+            add_candidate(
+                &mut candidates,
+                &mut best_score,
+                f32::MAX,
+                TestCandidate { score: 0.0, pattern: (0, false) },
+            );
+        }
+
+        // Two hypotheses with the same score merge to `score + ln(2)`, not `2 * score`.
+        let merged = &candidates[&(0, false)];
+        assert!((merged.score - 2f32.ln()).abs() < 1e-6);
+    }
+}
@@ -1,37 +1,60 @@
-use std::cmp::Ordering;
+use std::{cmp::Ordering, collections::HashMap};
 
-#[derive(Clone, Debug, PartialEq)]
-struct DecoderState {
+use crate::{
+    beam::{self, BeamCandidate},
+    LMStateRef, LM,
+};
+
+#[derive(Clone, Debug)]
+struct DecoderState<S> {
     score: f32,
     token: i32,
     prev_blank: bool,
     am_score: f32,
     lm_score: f32,
+    lm_state: LMStateRef<S>,
     parent_index: isize,
 }
 
-impl DecoderState {
-    fn cmp_without_score(&self, other: &DecoderState) -> Ordering {
-        if self.token != other.token {
-            self.token.cmp(&other.token)
-        } else if self.prev_blank != other.prev_blank {
-            self.prev_blank.cmp(&other.prev_blank)
-        } else {
-            Ordering::Equal
-        }
+impl<S> DecoderState<S> {
+    fn cmp_by_score(&self, other: &DecoderState<S>) -> Ordering {
+        self.score.partial_cmp(&other.score).unwrap()
     }
+}
 
-    fn cmp_without_score_then_score(&self, other: &DecoderState) -> Ordering {
-        let without_score = self.cmp_without_score(other);
-        if without_score != Ordering::Equal {
-            without_score
-        } else {
-            self.cmp_by_score(other)
-        }
+// Only the score participates in ordering: this is what lets `DecoderState`
+// sit directly in a `BinaryHeap` used as a bounded top-`beam_size` frontier.
+impl<S> PartialEq for DecoderState<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.score == other.score
     }
+}
 
-    fn cmp_by_score(&self, other: &DecoderState) -> Ordering {
-        self.score.partial_cmp(&other.score).unwrap()
+impl<S> Eq for DecoderState<S> {}
+
+impl<S> PartialOrd for DecoderState<S> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp_by_score(other))
+    }
+}
+
+impl<S> Ord for DecoderState<S> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.cmp_by_score(other)
+    }
+}
+
+impl<S> BeamCandidate for DecoderState<S> {
+    fn pattern_key(&self) -> (i32, bool) {
+        (self.token, self.prev_blank)
+    }
+
+    fn score(&self) -> f32 {
+        self.score
+    }
+
+    fn set_score(&mut self, score: f32) {
+        self.score = score;
     }
 }
 
@@ -40,16 +63,20 @@ pub struct DecoderOutput {
     score: f32,
     am_score: f32,
     lm_score: f32,
+    /// posterior probability of this hypothesis, from a softmax over the
+    /// scores of every hypothesis that survived to the final beam.
+    probs: f32,
     tokens: Vec<i32>,
 }
 
 impl DecoderOutput {
-    fn reserved(len: usize) -> Self {
+    pub(crate) fn new(score: f32, am_score: f32, lm_score: f32, probs: f32, tokens: Vec<i32>) -> Self {
         Self {
-            score: 0.0,
-            am_score: 0.0,
-            lm_score: 0.0,
-            tokens: vec![0; len],
+            score,
+            am_score,
+            lm_score,
+            probs,
+            tokens,
         }
     }
 }
@@ -60,27 +87,43 @@ pub struct DecoderOptions {
     pub beam_size_token: usize,
     /// the decoder will ignore paths whose score is more than this value lower than the best score.
     pub beam_threshold: f32,
+    /// weight applied to the LM score before it is folded into the beam score.
+    pub lm_weight: f32,
+    /// extra cost subtracted from a hypothesis every time it completes a word
+    /// the LM considers out of vocabulary, so unknown words don't silently
+    /// score as well as a genuine `<unk>` match would suggest. Only consulted
+    /// by [`crate::LexiconDecoder`]: `Decoder` has no notion of words, so
+    /// this field is silently ignored there.
+    pub oov_penalty: f32,
+    /// score added to a hypothesis every time it completes a word, to correct
+    /// for the fact that pure LM+AM scoring systematically favours shorter or
+    /// longer transcriptions depending on the model. Negative to discourage
+    /// inserting words, positive to encourage it. Only consulted by
+    /// [`crate::LexiconDecoder`]: `Decoder` has no notion of words, so this
+    /// field is silently ignored there.
+    pub word_insertion_penalty: f32,
 }
 
-pub struct Decoder {
+pub struct Decoder<L: LM> {
     options: DecoderOptions,
-    /// All the new candidates that proposed based on the previous step.
-    current_candidates: Vec<DecoderState>,
+    lm: L,
+    /// All the new candidates proposed based on the previous step, merged by
+    /// `(token, prev_blank)` pattern as they come in via [`beam::add_candidate`].
+    current_candidates: HashMap<(i32, bool), DecoderState<L::State>>,
     current_best_score: f32,
-    current_candidate_pointers: Vec<usize>,
     /// blank_index is the index of the blank token.
     blank: i32,
     /// hypothesis for each time step.
-    hypothesis: Vec<Vec<DecoderState>>,
+    hypothesis: Vec<Vec<DecoderState<L::State>>>,
 }
 
-impl Decoder {
-    pub fn new(options: DecoderOptions, blank: i32) -> Self {
+impl<L: LM> Decoder<L> {
+    pub fn new(options: DecoderOptions, lm: L, blank: i32) -> Self {
         Self {
             options,
-            current_candidates: Vec::new(),
+            lm,
+            current_candidates: HashMap::new(),
             current_best_score: f32::MIN,
-            current_candidate_pointers: Vec::new(),
             blank,
             hypothesis: Vec::new(),
         }
@@ -89,13 +132,13 @@ impl Decoder {
     pub fn decode(&mut self, data: &[f32], steps: usize, tokens: usize) -> Vec<DecoderOutput> {
         self.decode_begin();
         self.decode_step(data, steps, tokens);
-        self.decode_end();
+        self.decode_end(steps);
         self.get_all_hypothesis(steps)
     }
 
     fn decode_begin(&mut self) {
         self.reset_candidate();
-        // TODO: Compute the LM initial score.
+        let lm_state = self.lm.start();
         self.hypothesis.clear();
         self.hypothesis.push(Vec::new());
         self.hypothesis[0].push(DecoderState {
@@ -104,7 +147,8 @@ impl Decoder {
             prev_blank: false,
             am_score: 0.0,
             lm_score: 0.0,
-            parent_index: -1 /* ROOT */,
+            lm_state,
+            parent_index: -1, /* ROOT */
         });
     }
 
@@ -136,23 +180,24 @@ impl Decoder {
 
                     if token != self.blank && (token != prev_token || prev_hyp.prev_blank) {
                         // New token
-                        // TODO: Compute LM Score.
-                        add_candidate(
+                        let (lm_state, lm_score) = self.lm.score(&prev_hyp.lm_state, token, tokens);
+                        beam::add_candidate(
                             &mut self.current_candidates,
                             &mut self.current_best_score,
                             self.options.beam_threshold,
                             DecoderState {
-                                score,
+                                score: score + self.options.lm_weight * lm_score,
                                 token,
                                 prev_blank: false,
                                 am_score: prev_hyp.am_score + am_score,
-                                lm_score: 0.0,
+                                lm_score: prev_hyp.lm_score + lm_score,
+                                lm_state,
                                 parent_index: prev_hyp_idx as isize,
                             },
                         );
                     } else if token == self.blank {
                         // Blank
-                        add_candidate(
+                        beam::add_candidate(
                             &mut self.current_candidates,
                             &mut self.current_best_score,
                             self.options.beam_threshold,
@@ -161,13 +206,14 @@ impl Decoder {
                                 token,
                                 prev_blank: true,
                                 am_score: prev_hyp.am_score + am_score,
-                                lm_score: 0.0,
+                                lm_score: prev_hyp.lm_score,
+                                lm_state: prev_hyp.lm_state.clone(),
                                 parent_index: prev_hyp_idx as isize,
                             },
                         );
                     } else {
                         // Extend
-                        add_candidate(
+                        beam::add_candidate(
                             &mut self.current_candidates,
                             &mut self.current_best_score,
                             self.options.beam_threshold,
@@ -176,7 +222,8 @@ impl Decoder {
                                 token,
                                 prev_blank: false,
                                 am_score: prev_hyp.am_score + am_score,
-                                lm_score: 0.0,
+                                lm_score: prev_hyp.lm_score,
+                                lm_state: prev_hyp.lm_state.clone(),
                                 parent_index: prev_hyp_idx as isize,
                             },
                         );
@@ -188,103 +235,61 @@ impl Decoder {
         }
     }
 
-    fn decode_end(&mut self)  {
-        // TODO: Compute LM Score.
+    fn decode_end(&mut self, steps: usize) {
+        for hyp in self.hypothesis[steps].iter_mut() {
+            let (lm_state, lm_score) = self.lm.finish(&hyp.lm_state);
+            hyp.score += self.options.lm_weight * lm_score;
+            hyp.lm_score += lm_score;
+            hyp.lm_state = lm_state;
+        }
     }
 
     fn reset_candidate(&mut self) {
         self.current_best_score = f32::MIN;
         self.current_candidates.clear();
-        self.current_candidate_pointers.clear();
     }
 
-    /// Finalize candidates at the current time step.
-    /// This prunes the candidates and sort them by score.
+    /// Finalize candidates at the current time step: prune to the best
+    /// `beam_size` via a bounded min-heap, without sorting every candidate.
     fn finalize_candidate(&mut self, t: usize) {
-        // 1. Gather valid candidates.
-        // ================================================================
-        for (i, candidate) in self.current_candidates.iter().enumerate() {
-            if candidate.score > self.current_best_score - self.options.beam_threshold {
-                self.current_candidate_pointers.push(i);
-            }
-        }
-
-        // 2. Merge same patterns.
-        // ================================================================
-        // Sort candidates so that the same patterns are consecutive.
-        self.current_candidate_pointers.sort_by(|a, b| {
-            self.current_candidates[*a].cmp_without_score_then_score(&self.current_candidates[*b])
-        });
-        let mut n_candidates_after_merged = 1;
-        let mut last_ptr = self.current_candidate_pointers[0];
-        for i in 1..self.current_candidate_pointers.len() {
-            let ptr = self.current_candidate_pointers[i];
-            if self.current_candidates[ptr].cmp_without_score(&self.current_candidates[last_ptr]) != Ordering::Equal{
-                // Distinct pattern.
-                self.current_candidate_pointers[n_candidates_after_merged] = ptr;
-                n_candidates_after_merged += 1;
-                last_ptr = ptr;
-            } else {
-                // Same pattern.
-                let max_score = self.current_candidates[last_ptr].score.max(self.current_candidates[ptr].score);
-                let min_score = self.current_candidates[last_ptr].score.min(self.current_candidates[ptr].score);
-                self.current_candidates[last_ptr].score = max_score + libm::log1p(libm::exp(min_score as f64 - max_score as f64)) as f32;
-            }
-        }
-        self.current_candidate_pointers.truncate(n_candidates_after_merged);
-
-        // 3. Sort candidates.
-        if self.current_candidate_pointers.len() > self.options.beam_size {
-            pdqselect::select_by(&mut self.current_candidate_pointers, self.options.beam_size, |&a, &b| {
-                self.current_candidates[a].cmp_by_score(&self.current_candidates[b]).reverse()
-            });
-        }
-
-        // 4. Copy candidates to output.
-        let output = &mut self.hypothesis[t + 1];
-        output.clear();
-        for &ptr in self.current_candidate_pointers.iter().take(self.options.beam_size) {
-            output.push(self.current_candidates[ptr].clone());
-        }
+        let pruned = beam::prune_to_beam(
+            &mut self.current_candidates,
+            self.current_best_score,
+            self.options.beam_threshold,
+            self.options.beam_size,
+        );
+        self.hypothesis[t + 1] = pruned;
     }
 
     fn get_all_hypothesis(&self, final_step: usize) -> Vec<DecoderOutput> {
-        println!("{:?}", self.hypothesis);
-        self.hypothesis[final_step].iter().map(|hyp| {
-            let mut output = DecoderOutput::reserved(final_step);
-            output.score = hyp.score;
-            output.am_score = hyp.am_score;
-            output.lm_score = hyp.lm_score;
-            let mut hyp_ = hyp;
-            for i in (0..final_step).rev() {
-                output.tokens[i] = hyp_.token;
-                if hyp_.parent_index == -1 {
-                    break;
+        let hyps = &self.hypothesis[final_step];
+        let max_score = hyps.iter().map(|hyp| hyp.score).fold(f32::MIN, f32::max);
+        let sum_exp: f32 = hyps.iter().map(|hyp| (hyp.score - max_score).exp()).sum();
+        hyps.iter()
+            .map(|hyp| {
+                let probs = if sum_exp > 0.0 {
+                    (hyp.score - max_score).exp() / sum_exp
+                } else {
+                    0.0
+                };
+                let mut tokens = vec![0; final_step];
+                let mut hyp_ = hyp;
+                for i in (0..final_step).rev() {
+                    tokens[i] = hyp_.token;
+                    if hyp_.parent_index == -1 {
+                        break;
+                    }
+                    hyp_ = &self.hypothesis[i][hyp_.parent_index as usize];
                 }
-                hyp_ = &self.hypothesis[i][hyp_.parent_index as usize];
-            }
-            output
-        }).collect()
-    }
-}
-
-fn add_candidate(
-    output: &mut Vec<DecoderState>,
-    current_best_score: &mut f32,
-    beam_threshold: f32,
-    state: DecoderState,
-) {
-    if state.score > *current_best_score {
-        *current_best_score = state.score;
-    }
-    if state.score > *current_best_score - beam_threshold {
-        output.push(state);
+                DecoderOutput::new(hyp.score, hyp.am_score, hyp.lm_score, probs, tokens)
+            })
+            .collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{DecoderOptions, Decoder};
+    use crate::{Decoder, DecoderOptions, LMStateRef, ZeroLM, LM};
 
     #[test]
     fn it_works() {
@@ -292,8 +297,11 @@ mod tests {
             beam_size: 1,
             beam_size_token: 10,
             beam_threshold: f32::MAX,
+            lm_weight: 0.0,
+            oov_penalty: 0.0,
+            word_insertion_penalty: 0.0,
         };
-        let mut decoder = Decoder::new(options, 4);
+        let mut decoder = Decoder::new(options, ZeroLM, 4);
         let steps = 3;
         let tokens = 4;
         let data = &[
@@ -304,4 +312,53 @@ mod tests {
         let outputs = decoder.decode(data, steps, tokens);
         assert_eq!(outputs, Vec::new());
     }
+
+    /// An [`LM`] that scores every token, and the end of sentence, with the
+    /// same fixed value, so a test can tell exactly how much of it ended up
+    /// folded into the beam score.
+    #[derive(Clone, Copy)]
+    struct ConstantLM(f32);
+
+    impl LM for ConstantLM {
+        type State = ();
+
+        fn start(&mut self) -> LMStateRef<()> {
+            LMStateRef::new(())
+        }
+
+        fn score(&mut self, state: &LMStateRef<()>, _token: i32, _n_vocab: usize) -> (LMStateRef<()>, f32) {
+            (state.clone(), self.0)
+        }
+
+        fn finish(&mut self, state: &LMStateRef<()>) -> (LMStateRef<()>, f32) {
+            (state.clone(), self.0)
+        }
+    }
+
+    #[test]
+    fn lm_score_is_weighted_and_folded_into_the_beam_score() {
+        let options = DecoderOptions {
+            beam_size: 1,
+            beam_size_token: 10,
+            beam_threshold: f32::MAX,
+            lm_weight: 2.0,
+            oov_penalty: 0.0,
+            word_insertion_penalty: 0.0,
+        };
+        let mut decoder = Decoder::new(options, ConstantLM(3.0), 0);
+        let steps = 1;
+        let tokens = 4;
+        let data = &[0.0, 1.0, 0.0, 0.0];
+        let outputs = decoder.decode(data, steps, tokens);
+
+        assert_eq!(outputs.len(), 1);
+        let output = &outputs[0];
+        // am_score picks out token 1 (am_score 1.0); one token-score call and
+        // one end-of-sentence call each contribute the constant 3.0 to
+        // `lm_score`, and `score` folds both in scaled by `lm_weight`.
+        assert_eq!(output.am_score, 1.0);
+        assert_eq!(output.lm_score, 6.0);
+        assert_eq!(output.score, 1.0 + 2.0 * 6.0);
+        assert_eq!(output.tokens, vec![1]);
+    }
 }
\ No newline at end of file
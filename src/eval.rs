@@ -0,0 +1,274 @@
+//! Scoring decoder output against reference transcriptions.
+//!
+//! Both metrics work on already-detokenized sentences (e.g. a
+//! [`DecoderOutput`](crate::DecoderOutput) turned back into text via
+//! [`Dict`](crate::Dict)), since word error rate and BLEU are both defined
+//! over word sequences, not token ids.
+
+use std::collections::HashMap;
+
+/// The breakdown of a Levenshtein alignment between a hypothesis and a
+/// reference word sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WordErrorRate {
+    pub substitutions: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+    pub reference_words: usize,
+}
+
+impl WordErrorRate {
+    pub fn errors(&self) -> usize {
+        self.substitutions + self.insertions + self.deletions
+    }
+
+    /// `errors / reference_words`. `0.0` for an empty reference with no
+    /// errors, `+inf` for an empty reference with insertions.
+    pub fn rate(&self) -> f32 {
+        if self.reference_words == 0 {
+            return if self.errors() == 0 { 0.0 } else { f32::INFINITY };
+        }
+        self.errors() as f32 / self.reference_words as f32
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Match,
+    Sub,
+    Ins,
+    Del,
+}
+
+/// Word error rate between `hypothesis` and `reference`, computed via the
+/// Levenshtein edit-distance DP over word sequences (split on whitespace).
+pub fn word_error_rate(hypothesis: &str, reference: &str) -> WordErrorRate {
+    let hyp: Vec<&str> = hypothesis.split_whitespace().collect();
+    let refw: Vec<&str> = reference.split_whitespace().collect();
+    let n = hyp.len();
+    let m = refw.len();
+
+    let mut dist = vec![vec![0usize; m + 1]; n + 1];
+    let mut op = vec![vec![Op::Match; m + 1]; n + 1];
+    for (i, row) in op.iter_mut().enumerate().take(n + 1).skip(1) {
+        dist[i][0] = i;
+        row[0] = Op::Ins;
+    }
+    for j in 1..=m {
+        dist[0][j] = j;
+        op[0][j] = Op::Del;
+    }
+
+    for i in 1..=n {
+        for j in 1..=m {
+            if hyp[i - 1] == refw[j - 1] {
+                dist[i][j] = dist[i - 1][j - 1];
+                op[i][j] = Op::Match;
+            } else {
+                let sub = dist[i - 1][j - 1] + 1;
+                // Extra word in the hypothesis that isn't in the reference.
+                let ins = dist[i - 1][j] + 1;
+                // Reference word missing from the hypothesis.
+                let del = dist[i][j - 1] + 1;
+                let best = sub.min(ins).min(del);
+                dist[i][j] = best;
+                op[i][j] = if best == sub {
+                    Op::Sub
+                } else if best == ins {
+                    Op::Ins
+                } else {
+                    Op::Del
+                };
+            }
+        }
+    }
+
+    let (mut substitutions, mut insertions, mut deletions) = (0, 0, 0);
+    let (mut i, mut j) = (n, m);
+    while i > 0 || j > 0 {
+        match op[i][j] {
+            Op::Match => {
+                i -= 1;
+                j -= 1;
+            }
+            Op::Sub => {
+                substitutions += 1;
+                i -= 1;
+                j -= 1;
+            }
+            Op::Ins => {
+                insertions += 1;
+                i -= 1;
+            }
+            Op::Del => {
+                deletions += 1;
+                j -= 1;
+            }
+        }
+    }
+
+    WordErrorRate {
+        substitutions,
+        insertions,
+        deletions,
+        reference_words: m,
+    }
+}
+
+fn words(sentence: &str) -> Vec<&str> {
+    sentence.split_whitespace().collect()
+}
+
+fn ngram_counts<'a>(words: &'a [&'a str], order: usize) -> HashMap<&'a [&'a str], usize> {
+    let mut counts = HashMap::new();
+    if words.len() >= order {
+        for window in words.windows(order) {
+            *counts.entry(window).or_insert(0usize) += 1;
+        }
+    }
+    counts
+}
+
+/// Corpus-level BLEU aggregator: accumulates n-gram match/total counts (and
+/// hypothesis/closest-reference lengths) across many sentences before
+/// computing the final score, so short-sentence noise averages out the way
+/// it does in standard corpus BLEU implementations.
+///
+/// Supports multiple references per sentence: each n-gram's clipped count
+/// uses the maximum matching count across references, and the brevity
+/// penalty uses whichever reference length is closest to the hypothesis
+/// length for that sentence, following the dtrain scorer design.
+pub struct CorpusBleu {
+    max_order: usize,
+    matches: Vec<u64>,
+    totals: Vec<u64>,
+    hypothesis_length: u64,
+    reference_length: u64,
+}
+
+impl CorpusBleu {
+    pub fn new(max_order: usize) -> Self {
+        Self {
+            max_order,
+            matches: vec![0; max_order],
+            totals: vec![0; max_order],
+            hypothesis_length: 0,
+            reference_length: 0,
+        }
+    }
+
+    /// Score one more sentence against one or more references.
+    pub fn add(&mut self, hypothesis: &str, references: &[&str]) {
+        let hyp_words = words(hypothesis);
+        let ref_words: Vec<Vec<&str>> = references.iter().map(|r| words(r)).collect();
+
+        for order in 1..=self.max_order {
+            let hyp_ngrams = ngram_counts(&hyp_words, order);
+            let ref_ngrams: Vec<_> = ref_words.iter().map(|r| ngram_counts(r, order)).collect();
+
+            let mut matched = 0u64;
+            let mut total = 0u64;
+            for (gram, &count) in &hyp_ngrams {
+                total += count as u64;
+                let max_ref_count = ref_ngrams
+                    .iter()
+                    .map(|counts| *counts.get(gram).unwrap_or(&0))
+                    .max()
+                    .unwrap_or(0);
+                matched += count.min(max_ref_count) as u64;
+            }
+            self.matches[order - 1] += matched;
+            self.totals[order - 1] += total;
+        }
+
+        self.hypothesis_length += hyp_words.len() as u64;
+        self.reference_length += ref_words
+            .iter()
+            .map(|r| r.len())
+            .min_by_key(|&len| (len as i64 - hyp_words.len() as i64).abs())
+            .unwrap_or(0) as u64;
+    }
+
+    /// BLEU-N: brevity penalty times the geometric mean of the modified
+    /// n-gram precisions accumulated so far.
+    pub fn score(&self) -> f32 {
+        if self.hypothesis_length == 0 {
+            return 0.0;
+        }
+
+        let log_precision_sum: f32 = self
+            .matches
+            .iter()
+            .zip(&self.totals)
+            .map(|(&matched, &total)| {
+                if total == 0 {
+                    // No n-grams of this order in any hypothesis: contributes
+                    // nothing, rather than driving the whole score to zero.
+                    0.0
+                } else if matched == 0 {
+                    // Genuine zero overlap at this order: a real precision of
+                    // `0.0`, which must zero out the whole geometric mean, not
+                    // get floored into a false "perfect" precision.
+                    f32::NEG_INFINITY
+                } else {
+                    (matched as f32 / total as f32).ln()
+                }
+            })
+            .sum();
+        let geometric_mean = (log_precision_sum / self.max_order as f32).exp();
+
+        let brevity_penalty = if self.hypothesis_length >= self.reference_length {
+            1.0
+        } else {
+            (1.0 - self.reference_length as f32 / self.hypothesis_length as f32).exp()
+        };
+
+        brevity_penalty * geometric_mean
+    }
+}
+
+/// Convenience wrapper to BLEU-score a single sentence against one or more references.
+pub fn sentence_bleu(hypothesis: &str, references: &[&str], max_order: usize) -> f32 {
+    let mut corpus = CorpusBleu::new(max_order);
+    corpus.add(hypothesis, references);
+    corpus.score()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wer_counts_each_kind_of_error() {
+        let wer = word_error_rate("the quick fox", "the quick brown fox");
+        assert_eq!(wer.deletions, 1);
+        assert_eq!(wer.substitutions, 0);
+        assert_eq!(wer.insertions, 0);
+        assert_eq!(wer.reference_words, 4);
+    }
+
+    #[test]
+    fn wer_is_zero_for_identical_sentences() {
+        let wer = word_error_rate("a b c", "a b c");
+        assert_eq!(wer.errors(), 0);
+        assert_eq!(wer.rate(), 0.0);
+    }
+
+    #[test]
+    fn bleu_is_one_for_an_exact_match() {
+        let score = sentence_bleu("a b c d", &["a b c d"], 4);
+        assert!((score - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn bleu_uses_the_best_matching_reference() {
+        let score = sentence_bleu("a b c d", &["x y z w", "a b c d"], 4);
+        assert!((score - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn bleu_is_zero_for_no_overlap() {
+        let score = sentence_bleu("a b c d", &["w x y z"], 4);
+        assert_eq!(score, 0.0);
+    }
+}
@@ -0,0 +1,562 @@
+//! Lexicon-constrained, word-level beam search decoding.
+//!
+//! KenLM scores *words*, but the CTC acoustic model emits *letters/subword
+//! tokens*, so consulting the LM on every token (as [`crate::Decoder`] does)
+//! is semantically wrong: only whole, known words should ever be scored.
+//! [`Lexicon`] is a prefix trie over the acoustic model's token set whose
+//! leaves carry the corresponding KenLM word index, letting
+//! [`LexiconDecoder`] track, for every beam hypothesis, how far into a known
+//! word it has spelled so far.
+
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use crate::{
+    beam::{self, BeamCandidate},
+    lm::kenlm::{KenLM, KenLMState, KenLMWordIndex, Model},
+    Dict, DecoderOptions, DecoderOutput,
+};
+
+#[derive(Debug)]
+struct TrieNode {
+    children: HashMap<i32, usize>,
+    /// Set when this node is the last token of a word in the lexicon.
+    word: Option<KenLMWordIndex>,
+    /// Optimistic best score, in the sentence-start context, over every word
+    /// reachable through this node, used to give partially-spelled words a fair score during beam
+    /// pruning instead of penalizing them for not having finished a word yet.
+    /// Starts at negative infinity (not `0.0`) so a node with no word under it
+    /// never looks like it has an unbeatable, better-than-real-words score.
+    max_score: f32,
+}
+
+impl Default for TrieNode {
+    fn default() -> Self {
+        Self {
+            children: HashMap::new(),
+            word: None,
+            max_score: f32::NEG_INFINITY,
+        }
+    }
+}
+
+/// A prefix trie mapping token sequences, spelled the way the acoustic
+/// model's [`Dict`] spells them, to KenLM word indices.
+pub struct Lexicon {
+    nodes: Vec<TrieNode>,
+    unknown_word: KenLMWordIndex,
+}
+
+impl Lexicon {
+    pub const ROOT: usize = 0;
+
+    pub fn new(unknown_word: KenLMWordIndex) -> Self {
+        Self {
+            nodes: vec![TrieNode::default()],
+            unknown_word,
+        }
+    }
+
+    /// Load a lexicon file where each line is `word token1 token2 ...`,
+    /// e.g. `cat c a t`. Unknown tokens are skipped. Already [`smear`]ed
+    /// against `kenlm`, ready to hand straight to [`LexiconDecoder::new`].
+    ///
+    /// [`smear`]: Lexicon::smear
+    pub fn load(path: &Path, dict: &Dict, kenlm: &KenLM) -> std::io::Result<Self> {
+        let vocab = kenlm.model().vocab();
+        let mut lexicon = Self::new(vocab.index("<unk>"));
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let mut it = line.split_whitespace();
+            let word = match it.next() {
+                Some(word) => word,
+                None => continue,
+            };
+            let tokens: Vec<i32> = it.filter_map(|tok| dict.index(tok)).collect();
+            lexicon.insert(&tokens, vocab.index(word));
+        }
+        lexicon.smear(kenlm.model());
+        Ok(lexicon)
+    }
+
+    pub fn insert(&mut self, tokens: &[i32], word: KenLMWordIndex) {
+        let mut node = Self::ROOT;
+        for &token in tokens {
+            node = match self.nodes[node].children.get(&token) {
+                Some(&child) => child,
+                None => {
+                    self.nodes.push(TrieNode::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.insert(token, child);
+                    child
+                }
+            };
+        }
+        self.nodes[node].word = Some(word);
+    }
+
+    /// Follow `token` from `node`, if any word in the lexicon continues that way.
+    pub fn child(&self, node: usize, token: i32) -> Option<usize> {
+        self.nodes[node].children.get(&token).copied()
+    }
+
+    /// The KenLM word index completed at `node`, if any.
+    pub fn word_at(&self, node: usize) -> Option<KenLMWordIndex> {
+        self.nodes[node].word
+    }
+
+    pub fn max_score(&self, node: usize) -> f32 {
+        self.nodes[node].max_score
+    }
+
+    /// Smear every word's score in the sentence-start context (as given by
+    /// [`Model::begin_context`]) up to its prefixes, so a hypothesis
+    /// that has only spelled part of a word still gets an optimistic LM
+    /// estimate to compare against hypotheses that went a different way.
+    ///
+    /// [`Lexicon::load`] calls this automatically. Building a lexicon by hand
+    /// with [`Lexicon::new`]/[`Lexicon::insert`] instead, every node's
+    /// `max_score` stays at `f32::NEG_INFINITY` until this is called — decode
+    /// it with [`LexiconDecoder`] before that, and the first `smeared_estimate`
+    /// subtraction (`-inf - -inf`) produces a `NaN` score that panics the
+    /// first time two such hypotheses meet in the beam.
+    pub fn smear(&mut self, model: &Model) {
+        let start = model.begin_context();
+        for node in self.nodes.iter_mut() {
+            if let Some(word) = node.word {
+                let (_, score) = model.base_score(&start, word);
+                node.max_score = node.max_score.max(score);
+            }
+        }
+        // Children are always pushed after their parent, so a node's index
+        // is always smaller than any of its children's: walking from the
+        // highest index down to the root means every child is already
+        // finalized by the time its parent folds it in.
+        for i in (0..self.nodes.len()).rev() {
+            let children_max = self.nodes[i]
+                .children
+                .values()
+                .map(|&child| self.nodes[child].max_score)
+                .fold(f32::MIN, f32::max);
+            self.nodes[i].max_score = self.nodes[i].max_score.max(children_max);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct LexiconDecoderState {
+    score: f32,
+    token: i32,
+    prev_blank: bool,
+    am_score: f32,
+    lm_score: f32,
+    kenlm_state: KenLMState,
+    /// where this hypothesis currently sits in the lexicon trie.
+    lexicon_node: usize,
+    parent_index: isize,
+}
+
+impl LexiconDecoderState {
+    fn cmp_by_score(&self, other: &Self) -> Ordering {
+        self.score.partial_cmp(&other.score).unwrap()
+    }
+}
+
+// Only the score participates in ordering: this is what lets
+// `LexiconDecoderState` sit directly in a `BinaryHeap` used as a bounded
+// top-`beam_size` frontier.
+impl PartialEq for LexiconDecoderState {
+    fn eq(&self, other: &Self) -> bool {
+        self.score == other.score
+    }
+}
+
+impl Eq for LexiconDecoderState {}
+
+impl PartialOrd for LexiconDecoderState {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp_by_score(other))
+    }
+}
+
+impl Ord for LexiconDecoderState {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.cmp_by_score(other)
+    }
+}
+
+impl BeamCandidate for LexiconDecoderState {
+    fn pattern_key(&self) -> (i32, bool) {
+        (self.token, self.prev_blank)
+    }
+
+    fn score(&self) -> f32 {
+        self.score
+    }
+
+    fn set_score(&mut self, score: f32) {
+        self.score = score;
+    }
+}
+
+/// Word-level beam search decoder: like [`crate::Decoder`], but constrained
+/// to the words in a [`Lexicon`] and only scoring the LM once per completed
+/// word rather than once per token.
+pub struct LexiconDecoder<'a> {
+    options: DecoderOptions,
+    lm: &'a mut KenLM,
+    lexicon: &'a Lexicon,
+    /// index of the token that marks a word boundary (e.g. `|` or space).
+    word_separator: i32,
+    blank: i32,
+    /// All the new candidates proposed based on the previous step, merged by
+    /// `(token, prev_blank)` pattern as they come in via [`beam::add_candidate`].
+    current_candidates: HashMap<(i32, bool), LexiconDecoderState>,
+    current_best_score: f32,
+    hypothesis: Vec<Vec<LexiconDecoderState>>,
+}
+
+impl<'a> LexiconDecoder<'a> {
+    pub fn new(
+        options: DecoderOptions,
+        lm: &'a mut KenLM,
+        lexicon: &'a Lexicon,
+        blank: i32,
+        word_separator: i32,
+    ) -> Self {
+        Self {
+            options,
+            lm,
+            lexicon,
+            word_separator,
+            blank,
+            current_candidates: HashMap::new(),
+            current_best_score: f32::MIN,
+            hypothesis: Vec::new(),
+        }
+    }
+
+    pub fn decode(&mut self, data: &[f32], steps: usize, tokens: usize) -> Vec<DecoderOutput> {
+        self.decode_begin();
+        self.decode_step(data, steps, tokens);
+        self.decode_end(steps);
+        self.get_all_hypothesis(steps)
+    }
+
+    fn decode_begin(&mut self) {
+        self.reset_candidate();
+        self.hypothesis.clear();
+        self.hypothesis.push(Vec::new());
+        self.hypothesis[0].push(LexiconDecoderState {
+            score: 0.0,
+            token: self.blank,
+            prev_blank: false,
+            am_score: 0.0,
+            lm_score: 0.0,
+            kenlm_state: self.lm.model().begin_context(),
+            lexicon_node: Lexicon::ROOT,
+            parent_index: -1,
+        });
+    }
+
+    fn decode_step(&mut self, data: &[f32], steps: usize, tokens: usize) {
+        while self.hypothesis.len() < steps + 2 {
+            self.hypothesis.push(Vec::new());
+        }
+
+        let mut target_index = (0..tokens).collect::<Vec<_>>();
+        for t in 0..steps {
+            if tokens > self.options.beam_size_token {
+                pdqselect::select_by(&mut target_index, self.options.beam_size_token, |&a, &b| {
+                    data[t * tokens + a]
+                        .partial_cmp(&data[t * tokens + b])
+                        .unwrap()
+                        .reverse()
+                });
+            }
+            self.reset_candidate();
+            for (prev_hyp_idx, prev_hyp) in self.hypothesis[t].iter().enumerate() {
+                let prev_token = prev_hyp.token;
+                for &target in target_index.iter().take(self.options.beam_size_token) {
+                    let token = target as i32;
+                    let am_score = data[t * tokens + target];
+                    let score = prev_hyp.score + am_score;
+
+                    if token == self.blank {
+                        beam::add_candidate(
+                            &mut self.current_candidates,
+                            &mut self.current_best_score,
+                            self.options.beam_threshold,
+                            LexiconDecoderState {
+                                score,
+                                token,
+                                prev_blank: true,
+                                am_score: prev_hyp.am_score + am_score,
+                                lm_score: prev_hyp.lm_score,
+                                kenlm_state: prev_hyp.kenlm_state.clone(),
+                                lexicon_node: prev_hyp.lexicon_node,
+                                parent_index: prev_hyp_idx as isize,
+                            },
+                        );
+                    } else if token == prev_token && !prev_hyp.prev_blank {
+                        // Extend: repeating the same non-blank token without an
+                        // intervening blank collapses in CTC, no new letter spelled.
+                        beam::add_candidate(
+                            &mut self.current_candidates,
+                            &mut self.current_best_score,
+                            self.options.beam_threshold,
+                            LexiconDecoderState {
+                                score,
+                                token,
+                                prev_blank: false,
+                                am_score: prev_hyp.am_score + am_score,
+                                lm_score: prev_hyp.lm_score,
+                                kenlm_state: prev_hyp.kenlm_state.clone(),
+                                lexicon_node: prev_hyp.lexicon_node,
+                                parent_index: prev_hyp_idx as isize,
+                            },
+                        );
+                    } else if token == self.word_separator {
+                        // Word boundary: score the word we just finished spelling.
+                        // `score` still carries the optimistic smeared estimate
+                        // accumulated letter by letter while spelling this word
+                        // (see the "new letter" branch below); `score_word` undoes
+                        // exactly that much before folding in the real word score,
+                        // so the two don't both land in the total. A boundary
+                        // reached at the trie root (nothing spelled since the
+                        // last one) completes no word, so nothing is scored.
+                        let (kenlm_state, lm_score, word_score) =
+                            if prev_hyp.lexicon_node == Lexicon::ROOT {
+                                (prev_hyp.kenlm_state.clone(), 0.0, 0.0)
+                            } else {
+                                let (kenlm_state, lm_score, smeared_estimate) = Self::score_word(
+                                    self.lm.model(),
+                                    self.lexicon,
+                                    &self.options,
+                                    &prev_hyp.kenlm_state,
+                                    prev_hyp.lexicon_node,
+                                );
+                                let word_score = self.options.lm_weight
+                                    * (lm_score - smeared_estimate)
+                                    + self.options.word_insertion_penalty;
+                                (kenlm_state, lm_score, word_score)
+                            };
+                        let lexicon_node = Lexicon::ROOT;
+                        beam::add_candidate(
+                            &mut self.current_candidates,
+                            &mut self.current_best_score,
+                            self.options.beam_threshold,
+                            LexiconDecoderState {
+                                score: score + word_score,
+                                token,
+                                prev_blank: false,
+                                am_score: prev_hyp.am_score + am_score,
+                                lm_score: prev_hyp.lm_score + lm_score,
+                                kenlm_state,
+                                lexicon_node,
+                                parent_index: prev_hyp_idx as isize,
+                            },
+                        );
+                    } else {
+                        // New letter: only keep it if it continues some word in
+                        // the lexicon, scored optimistically by the smeared trie.
+                        // Only the *delta* between this node's and the parent's
+                        // smeared estimate is added, not the full estimate, so
+                        // that spelling an N-letter word sums (telescopes) to a
+                        // single estimate rather than N of them.
+                        if let Some(lexicon_node) = self.lexicon.child(prev_hyp.lexicon_node, token) {
+                            let smear_delta = self.lexicon.max_score(lexicon_node)
+                                - self.lexicon.max_score(prev_hyp.lexicon_node);
+                            let lm_score = self.options.lm_weight * smear_delta;
+                            beam::add_candidate(
+                                &mut self.current_candidates,
+                                &mut self.current_best_score,
+                                self.options.beam_threshold,
+                                LexiconDecoderState {
+                                    score: score + lm_score,
+                                    token,
+                                    prev_blank: false,
+                                    am_score: prev_hyp.am_score + am_score,
+                                    lm_score: prev_hyp.lm_score,
+                                    kenlm_state: prev_hyp.kenlm_state.clone(),
+                                    lexicon_node,
+                                    parent_index: prev_hyp_idx as isize,
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+            self.finalize_candidate(t);
+        }
+    }
+
+    fn decode_end(&mut self, steps: usize) {
+        for hyp in self.hypothesis[steps].iter_mut() {
+            // Audio can end mid-word (no trailing word separator), but the
+            // hypothesis has still been carrying a smeared estimate for
+            // whatever it spelled so far, so score that word the same way
+            // `decode_step`'s word-boundary arm does, OOV fallback included,
+            // rather than only when `lexicon_node` happens to sit on a
+            // completed word. A hypothesis at the trie root has spelled
+            // nothing since its last boundary, so it has no word to score.
+            if hyp.lexicon_node != Lexicon::ROOT {
+                let (kenlm_state, lm_score, smeared_estimate) = Self::score_word(
+                    self.lm.model(),
+                    self.lexicon,
+                    &self.options,
+                    &hyp.kenlm_state,
+                    hyp.lexicon_node,
+                );
+                hyp.score += self.options.lm_weight * (lm_score - smeared_estimate)
+                    + self.options.word_insertion_penalty;
+                hyp.lm_score += lm_score;
+                hyp.kenlm_state = kenlm_state;
+            }
+
+            let eos = self.lm.model().vocab().end_sentence();
+            let (_, score) = self.lm.model().base_score(&hyp.kenlm_state, eos);
+            hyp.score += self.options.lm_weight * score;
+            hyp.lm_score += score;
+        }
+    }
+
+    /// Score the word ending (or still being spelled) at `lexicon_node`,
+    /// applying `oov_penalty` if the LM considers it unknown, alongside the
+    /// optimistic smeared estimate accumulated while spelling it that must
+    /// be undone to avoid double-counting it on top of the real score.
+    /// Shared by `decode_step`'s word-boundary arm and `decode_end`, which
+    /// both need to reconcile a hypothesis's smeared estimate against a real
+    /// LM score, just at different points in the audio.
+    fn score_word(
+        model: &Model,
+        lexicon: &Lexicon,
+        options: &DecoderOptions,
+        kenlm_state: &KenLMState,
+        lexicon_node: usize,
+    ) -> (KenLMState, f32, f32) {
+        let word = lexicon.word_at(lexicon_node).unwrap_or(lexicon.unknown_word);
+        let (next_kenlm_state, full_score) = model.full_score(kenlm_state, word);
+        let lm_score = if full_score.is_oov {
+            full_score.log_prob - options.oov_penalty
+        } else {
+            full_score.log_prob
+        };
+        let smeared_estimate = lexicon.max_score(lexicon_node) - lexicon.max_score(Lexicon::ROOT);
+        (next_kenlm_state, lm_score, smeared_estimate)
+    }
+
+    fn reset_candidate(&mut self) {
+        self.current_best_score = f32::MIN;
+        self.current_candidates.clear();
+    }
+
+    /// Finalize candidates at the current time step: prune to the best
+    /// `beam_size` via a bounded min-heap, without sorting every candidate.
+    fn finalize_candidate(&mut self, t: usize) {
+        let pruned = beam::prune_to_beam(
+            &mut self.current_candidates,
+            self.current_best_score,
+            self.options.beam_threshold,
+            self.options.beam_size,
+        );
+        self.hypothesis[t + 1] = pruned;
+    }
+
+    fn get_all_hypothesis(&self, final_step: usize) -> Vec<DecoderOutput> {
+        let hyps = &self.hypothesis[final_step];
+        let max_score = hyps.iter().map(|hyp| hyp.score).fold(f32::MIN, f32::max);
+        let sum_exp: f32 = hyps.iter().map(|hyp| (hyp.score - max_score).exp()).sum();
+        hyps.iter()
+            .map(|hyp| {
+                let probs = if sum_exp > 0.0 {
+                    (hyp.score - max_score).exp() / sum_exp
+                } else {
+                    0.0
+                };
+                let mut tokens = vec![0; final_step];
+                let mut hyp_ = hyp;
+                for i in (0..final_step).rev() {
+                    tokens[i] = hyp_.token;
+                    if hyp_.parent_index == -1 {
+                        break;
+                    }
+                    hyp_ = &self.hypothesis[i][hyp_.parent_index as usize];
+                }
+                DecoderOutput::new(hyp.score, hyp.am_score, hyp.lm_score, probs, tokens)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    /// `Lexicon::load` smears the lexicon it builds, so `LexiconDecoder` never
+    /// sees a node stuck at `f32::NEG_INFINITY`'s `max_score` — the condition
+    /// that used to turn the very first `smeared_estimate` subtraction into a
+    /// `NaN` and panic inside `LexiconDecoderState`'s `Ord` the first time two
+    /// such hypotheses landed in the same beam. Run `decode` end-to-end,
+    /// including audio that ends mid-word, to exercise both `decode_step`'s
+    /// word-boundary reconciliation and `decode_end`'s.
+    #[test]
+    fn decode_runs_end_to_end_after_load_smears_the_lexicon() {
+        let dict = Dict::parse(File::open("data/letter.dict").unwrap()).unwrap();
+        let mut kenlm = KenLM::new(Path::new("data/overfit.arpa"), &dict).unwrap();
+        let lexicon = Lexicon::load(Path::new("data/lexicon.txt"), &dict, &kenlm).unwrap();
+
+        let blank = dict.index("#").unwrap();
+        let word_separator = dict.index("|").unwrap();
+        let options = DecoderOptions {
+            beam_size: 10,
+            beam_size_token: 10,
+            beam_threshold: f32::MAX,
+            lm_weight: 1.0,
+            oov_penalty: 5.0,
+            word_insertion_penalty: 0.0,
+        };
+        let mut decoder = LexiconDecoder::new(options, &mut kenlm, &lexicon, blank, word_separator);
+
+        let tokens = dict.len();
+        let mut data = vec![0.0f32; 3 * tokens];
+        data[dict.index("M").unwrap() as usize] = 5.0;
+        data[1 * tokens + word_separator as usize] = 5.0;
+        data[2 * tokens + dict.index("I").unwrap() as usize] = 5.0;
+
+        // `DecoderOutput`'s fields are private to `decoder`, so the strongest
+        // assertion available here is that `decode` returns hypotheses at
+        // all: before the `smear` fix, a NaN score would have panicked
+        // inside the beam before ever reaching this point.
+        let outputs = decoder.decode(&data, 3, tokens);
+        assert!(!outputs.is_empty());
+    }
+
+    #[test]
+    fn trie_tracks_word_boundaries_along_shared_prefixes() {
+        let mut lexicon = Lexicon::new(0);
+        // "cat" = [3, 1, 20], "car" = [3, 1, 18], sharing the "ca" prefix.
+        lexicon.insert(&[3, 1, 20], 101);
+        lexicon.insert(&[3, 1, 18], 102);
+
+        let c = lexicon.child(Lexicon::ROOT, 3).unwrap();
+        let ca = lexicon.child(c, 1).unwrap();
+        assert_eq!(lexicon.word_at(ca), None);
+
+        let cat = lexicon.child(ca, 20).unwrap();
+        assert_eq!(lexicon.word_at(cat), Some(101));
+
+        let car = lexicon.child(ca, 18).unwrap();
+        assert_eq!(lexicon.word_at(car), Some(102));
+
+        // Spelling a continuation not in the lexicon just dead-ends.
+        assert_eq!(lexicon.child(ca, 99), None);
+    }
+}
@@ -1,12 +1,20 @@
+mod beam;
 mod decoder;
 mod dict;
+mod eval;
+mod lexicon;
 mod lm;
 
 pub use decoder::{
-    BeamSearchDecoder, BeamSearchDecoderOptions, Decoder, DecoderOutput, GreedyDecoder,
+    BeamSearchDecoder, BeamSearchDecoderOptions, Decoder, DecoderOptions, DecoderOutput,
+    GreedyDecoder,
 };
 pub use dict::Dict;
+pub use eval::{sentence_bleu, word_error_rate, CorpusBleu, WordErrorRate};
+pub use lexicon::{Lexicon, LexiconDecoder};
+#[cfg(feature = "kenlm")]
+pub use lm::interpolated::{InterpolatedLM, InterpolationMode};
 #[cfg(feature = "kenlm")]
 pub use lm::kenlm::KenLM;
-pub use lm::kenlm::Model;
+pub use lm::kenlm::{FullScoreReturn, Model, WordScore};
 pub use lm::{LMStateRef, ZeroLM, LM};
@@ -0,0 +1,140 @@
+//! Fusing several KenLM models into a single [`LM`] at scoring time, so a
+//! generic and a domain-specific ARPA can be combined without retraining or
+//! offline-merging them into one model file.
+
+use std::path::Path;
+
+use crate::Dict;
+
+use super::{
+    kenlm::{KenLM, KenLMState},
+    LMStateRef, LM,
+};
+
+/// How the per-model log-probabilities of an [`InterpolatedLM`] are
+/// recombined into a single score.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InterpolationMode {
+    /// `combined = Σ wᵢ · log pᵢ`. Cheap, and monotone in each sub-model's
+    /// score, which is what beam search pruning wants.
+    LogLinear,
+    /// `combined = log Σ wᵢ · exp(log pᵢ)`, the true linear mixture,
+    /// computed via log-sum-exp to avoid underflow.
+    Linear,
+}
+
+/// A [`LM`] that interpolates the scores of several KenLM models, each with
+/// its own weight and its own vocabulary→index mapping against the shared
+/// [`Dict`].
+pub struct InterpolatedLM {
+    models: Vec<(KenLM, f32)>,
+    mode: InterpolationMode,
+}
+
+impl InterpolatedLM {
+    pub fn new(
+        models: &[(&Path, f32)],
+        dict: &Dict,
+        mode: InterpolationMode,
+    ) -> std::io::Result<Self> {
+        let models = models
+            .iter()
+            .map(|(path, weight)| Ok((KenLM::new(path, dict)?, *weight)))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok(Self { models, mode })
+    }
+
+    fn combine(&self, scores: &[f32]) -> f32 {
+        match self.mode {
+            InterpolationMode::LogLinear => self
+                .models
+                .iter()
+                .zip(scores)
+                .map(|((_, weight), score)| weight * score)
+                .sum(),
+            InterpolationMode::Linear => {
+                // KenLM scores are log10 probabilities: convert them to natural
+                // log for `log_sum_exp`, and the result back to log10.
+                let weighted_log_scores: Vec<f32> = self
+                    .models
+                    .iter()
+                    .zip(scores)
+                    .map(|((_, weight), score)| weight.ln() + score * std::f32::consts::LN_10)
+                    .collect();
+                log_sum_exp(&weighted_log_scores) / std::f32::consts::LN_10
+            }
+        }
+    }
+}
+
+/// `log Σ exp(values)`, computed by factoring out the largest value to avoid
+/// overflowing/underflowing the intermediate `exp`.
+fn log_sum_exp(values: &[f32]) -> f32 {
+    let max = values.iter().cloned().fold(f32::MIN, f32::max);
+    let sum: f64 = values.iter().map(|&v| libm::exp((v - max) as f64)).sum();
+    max + libm::log(sum) as f32
+}
+
+impl LM for InterpolatedLM {
+    type State = Vec<LMStateRef<KenLMState>>;
+
+    fn start(&mut self) -> LMStateRef<Self::State> {
+        let states = self
+            .models
+            .iter_mut()
+            .map(|(model, _)| model.start())
+            .collect();
+        LMStateRef::new(states)
+    }
+
+    fn score(
+        &mut self,
+        state: &LMStateRef<Self::State>,
+        token: i32,
+        n_vocab: usize,
+    ) -> (LMStateRef<Self::State>, f32) {
+        let mut next_states = Vec::with_capacity(self.models.len());
+        let mut scores = Vec::with_capacity(self.models.len());
+        for ((model, _), sub_state) in self
+            .models
+            .iter_mut()
+            .zip(state.borrow_internal_state())
+        {
+            let (next_state, score) = model.score(sub_state, token, n_vocab);
+            next_states.push(next_state);
+            scores.push(score);
+        }
+        let combined = self.combine(&scores);
+        (state.child(token, n_vocab, next_states), combined)
+    }
+
+    fn finish(&mut self, state: &LMStateRef<Self::State>) -> (LMStateRef<Self::State>, f32) {
+        let mut next_states = Vec::with_capacity(self.models.len());
+        let mut scores = Vec::with_capacity(self.models.len());
+        for ((model, _), sub_state) in self
+            .models
+            .iter_mut()
+            .zip(state.borrow_internal_state())
+        {
+            let (next_state, score) = model.finish(sub_state);
+            next_states.push(next_state);
+            scores.push(score);
+        }
+        let combined = self.combine(&scores);
+        (state.child(i32::MAX, 0, next_states), combined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_sum_exp_matches_the_linear_mixture() {
+        // exp(ln 1.0) + exp(ln 0.5) = 1.0 + 0.5 = 1.5, so the log-sum-exp
+        // should recover ln(1.5) exactly as the naive (overflow-prone) sum would.
+        let values = [1.0f32.ln(), 0.5f32.ln()];
+        let result = log_sum_exp(&values);
+        assert!((result - 1.5f32.ln()).abs() < 1e-6);
+    }
+}
@@ -15,6 +15,23 @@ use super::LM;
 
 pub type KenLMWordIndex = ctclib_kenlm_sys::lm_WordIndex;
 
+/// KenLM reserves word index `0` for `<unk>`; any token mapped to it is, by
+/// definition, out of vocabulary.
+pub const UNKNOWN_WORD_INDEX: KenLMWordIndex = 0;
+
+/// The detailed result of scoring one word, as KenLM computes it internally:
+/// `base_score` only surfaces the aggregate log-prob and throws the rest away.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FullScoreReturn {
+    /// log-prob of the word given the context, same convention as `base_score`.
+    pub log_prob: f32,
+    /// length of the n-gram match KenLM used to produce `log_prob`. Shorter
+    /// than the model order means the word backed off to a lower-order estimate.
+    pub ngram_length: i32,
+    /// whether `token` was the reserved out-of-vocabulary index.
+    pub is_oov: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct KenLMState(ctclib_kenlm_sys::lm_ngram_State);
 
@@ -90,6 +107,35 @@ impl Model {
             (outstate, score)
         })
     }
+
+    /// Like [`Model::base_score`], but also returns the matched n-gram length
+    /// and whether `token` was out of vocabulary, instead of discarding that
+    /// information.
+    pub fn full_score(
+        &self,
+        state: &KenLMState,
+        token: KenLMWordIndex,
+    ) -> (KenLMState, FullScoreReturn) {
+        state.with_ptr(|state_ptr| {
+            let mut outstate = KenLMState::new();
+            let mut raw: ctclib_kenlm_sys::lm_base_FullScoreReturn = unsafe { std::mem::zeroed() };
+            outstate.with_mut_ptr(|out| unsafe {
+                ctclib_kenlm_sys::lm_base_Model_FullScore(
+                    self.0,
+                    state_ptr as *const _,
+                    token as u32,
+                    out as *mut _,
+                    &mut raw as *mut _,
+                )
+            });
+            let full_score = FullScoreReturn {
+                log_prob: raw.prob,
+                ngram_length: raw.ngram_length as i32,
+                is_oov: token == UNKNOWN_WORD_INDEX,
+            };
+            (outstate, full_score)
+        })
+    }
 }
 
 impl Drop for Model {
@@ -137,6 +183,16 @@ fn load_model_and_get_vocab() {
     std::mem::drop(model);
 }
 
+/// The score KenLM gave a single word of a sentence, as returned by
+/// [`KenLM::sentence_score_with_breakdown`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct WordScore {
+    pub word: String,
+    pub log_prob: f32,
+    pub ngram_length: i32,
+    pub is_oov: bool,
+}
+
 /// KenLM integration for ctc decoding.
 /// KenLM is a n-gram language model library written in C++.
 /// See https://github.com/kpu/kenlm for more details about KenLM itself.
@@ -166,6 +222,13 @@ impl KenLM {
         })
     }
 
+    /// The underlying KenLM model, for callers (e.g. the lexicon decoder)
+    /// that need to score whole words directly instead of going through the
+    /// generic [`LM`] trait, which only ever sees one token at a time.
+    pub(crate) fn model(&self) -> &Model {
+        &self.model
+    }
+
     pub fn perplexity(&self, sentence: &str) -> f32 {
         let nb_words = sentence.split_whitespace().count() as f32 + 1f32; // account for </s>
 
@@ -191,6 +254,33 @@ impl KenLM {
             .base_score(&state, self.model.vocab().end_sentence());
         total + score
     }
+
+    /// Like [`KenLM::sentence_score`], but also returns a per-word breakdown
+    /// of what KenLM actually matched, useful for diagnosing why a sentence's
+    /// perplexity is high.
+    pub fn sentence_score_with_breakdown(&self, sentence: &str) -> (f32, Vec<WordScore>) {
+        let words: Vec<&str> = sentence.split_whitespace().collect();
+        let mut total = 0f32;
+        let mut breakdown = Vec::with_capacity(words.len());
+
+        let mut state = self.model.begin_context();
+        for word in &words {
+            let token_id = self.model.vocab().index(word);
+            let (new_state, full_score) = self.model.full_score(&state, token_id);
+            total += full_score.log_prob;
+            breakdown.push(WordScore {
+                word: (*word).to_string(),
+                log_prob: full_score.log_prob,
+                ngram_length: full_score.ngram_length,
+                is_oov: full_score.is_oov,
+            });
+            state = new_state;
+        }
+        let (_, score) = self
+            .model
+            .base_score(&state, self.model.vocab().end_sentence());
+        (total + score, breakdown)
+    }
 }
 
 impl LM for KenLM {
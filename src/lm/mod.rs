@@ -0,0 +1,90 @@
+//! Language model abstraction used by the beam-search decoder.
+//!
+//! A [`LM`] is anything that can score a token given the state carried by a
+//! decoder hypothesis so far. [`KenLM`](kenlm::KenLM) and `InterpolatedLM`
+//! (several KenLM models combined) are the real implementations, while
+//! [`ZeroLM`] (a no-op LM that always scores `0.0`) lets callers run the
+//! decoder without any language model at all.
+
+use std::{fmt, rc::Rc};
+
+#[cfg(feature = "kenlm")]
+pub mod interpolated;
+pub mod kenlm;
+
+/// A reference-counted handle to the internal state of a [`LM`], shared
+/// cheaply across the many decoder hypotheses that descend from the same
+/// prefix.
+pub struct LMStateRef<S>(Rc<S>);
+
+impl<S> LMStateRef<S> {
+    pub fn new(internal: S) -> Self {
+        Self(Rc::new(internal))
+    }
+
+    /// Build the state reached by scoring `token` from this state. `n_vocab`
+    /// is threaded through so callers can use an index outside the normal
+    /// `0..n_vocab` range (e.g. for the end-of-sentence score) without it
+    /// colliding with a real token.
+    pub fn child(&self, _token: i32, _n_vocab: usize, internal: S) -> Self {
+        Self::new(internal)
+    }
+
+    pub fn borrow_internal_state(&self) -> &S {
+        &self.0
+    }
+}
+
+impl<S> Clone for LMStateRef<S> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for LMStateRef<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A language model that can be consulted during beam search decoding.
+pub trait LM {
+    type State;
+
+    /// The state of an empty hypothesis, before any token has been scored.
+    fn start(&mut self) -> LMStateRef<Self::State>;
+
+    /// Score `token` given `state`, returning the resulting state and the
+    /// incremental log-probability to add to the hypothesis' score.
+    fn score(
+        &mut self,
+        state: &LMStateRef<Self::State>,
+        token: i32,
+        n_vocab: usize,
+    ) -> (LMStateRef<Self::State>, f32);
+
+    /// Score the end of the sentence, once a hypothesis is complete.
+    fn finish(&mut self, state: &LMStateRef<Self::State>) -> (LMStateRef<Self::State>, f32);
+}
+
+/// A no-op [`LM`] that never influences decoding; every token and the
+/// end-of-sentence both score `0.0`. Used when decoding without a language
+/// model.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ZeroLM;
+
+impl LM for ZeroLM {
+    type State = ();
+
+    fn start(&mut self) -> LMStateRef<()> {
+        LMStateRef::new(())
+    }
+
+    fn score(&mut self, state: &LMStateRef<()>, _token: i32, _n_vocab: usize) -> (LMStateRef<()>, f32) {
+        (state.clone(), 0.0)
+    }
+
+    fn finish(&mut self, state: &LMStateRef<()>) -> (LMStateRef<()>, f32) {
+        (state.clone(), 0.0)
+    }
+}
@@ -19,3 +19,22 @@ fn kenlm_model_works() {
         assert_eq!(score, -2.8997345);
     }
 }
+
+#[test]
+fn sentence_score_with_breakdown_reports_per_word_scores() {
+    let dict = Dict::parse(File::open("data/letter.dict").unwrap()).unwrap();
+    let kenlm = KenLM::new(&Path::new("data/overfit.arpa"), &dict).unwrap();
+    let (total, breakdown) = kenlm.sentence_score_with_breakdown("M I");
+
+    assert_eq!(breakdown.len(), 2);
+    assert_eq!(breakdown[0].word, "M");
+    assert_eq!(breakdown[0].log_prob, -0.045306083);
+    assert!(!breakdown[0].is_oov);
+    assert_eq!(breakdown[1].word, "I");
+    assert_eq!(breakdown[1].log_prob, -0.019120596);
+    assert!(!breakdown[1].is_oov);
+
+    // Matches `kenlm_model_works`'s `finish` score for the same "M I" context.
+    let expected_total: f32 = breakdown.iter().map(|w| w.log_prob).sum::<f32>() - 2.8997345;
+    assert_eq!(total, expected_total);
+}